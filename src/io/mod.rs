@@ -5,20 +5,39 @@
 use crypto::digest::Digest;
 use crypto::sha3;
 use flate2::read::GzDecoder;
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
+#[cfg(feature = "std")]
 use std::fs::File;
-use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::io::{self, Cursor, Seek, SeekFrom};
+#[cfg(feature = "std")]
 use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use std::fmt;
 
 use errors::{Error, ErrorKind, Result};
 use status::StatusBackend;
 
+pub mod traits;
+pub use self::traits::{Read, Write};
+
+// Providers that need a real filesystem, subprocesses, or the network are only
+// available with the `std` feature; the in-memory `memory`, `stack`, and
+// `zipbundle` providers keep working in a no_std build.
+#[cfg(feature = "std")]
 pub mod filesystem;
+#[cfg(feature = "std")]
 pub mod genuine_stdout;
 //pub mod hyper_seekable; -- Not currently used, but nice code to keep around.
+#[cfg(feature = "std")]
 pub mod itarbundle;
+#[cfg(feature = "std")]
 pub mod local_cache;
 pub mod memory;
+#[cfg(feature = "std")]
+pub mod process;
 pub mod stack;
 pub mod zipbundle;
 
@@ -38,19 +57,70 @@ pub trait InputFeatures: Read {
 pub type InputHandle = Box<InputFeatures>;
 
 
+/// The algorithm used to fingerprint an output's contents.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DigestAlgorithm {
+    /// 256-bit SHA-3 -- the default content fingerprint.
+    Sha3_256,
+
+    /// Skip hashing entirely. Useful for large intermediate outputs whose
+    /// fingerprint is never consulted, so paying the hashing cost is waste.
+    None,
+}
+
+impl DigestAlgorithm {
+    fn start(self) -> DigestState {
+        match self {
+            DigestAlgorithm::Sha3_256 => DigestState::Sha3(sha3::Sha3::sha3_256()),
+            DigestAlgorithm::None => DigestState::None,
+        }
+    }
+}
+
+enum DigestState {
+    Sha3(sha3::Sha3),
+    None,
+}
+
+impl DigestState {
+    fn input(&mut self, buf: &[u8]) {
+        if let DigestState::Sha3(ref mut d) = *self {
+            d.input(buf);
+        }
+    }
+
+    fn result(&mut self) -> Option<Vec<u8>> {
+        match *self {
+            DigestState::Sha3(ref mut d) => {
+                let mut r = vec![0u8; 32];
+                d.result(&mut r);
+                Some(r)
+            },
+            DigestState::None => None,
+        }
+    }
+}
+
+
 pub struct OutputHandle {
     name: OsString,
     inner: Box<Write>,
-    digest: sha3::Sha3,
+    digest: DigestState,
 }
 
 
 impl OutputHandle {
     pub fn new<T: 'static + Write>(name: &OsStr, inner: T) -> OutputHandle {
+        OutputHandle::new_with_digest(name, inner, DigestAlgorithm::Sha3_256)
+    }
+
+    /// Create an output handle that fingerprints its contents with the given
+    /// algorithm, or skips hashing entirely if `algo` is `DigestAlgorithm::None`.
+    pub fn new_with_digest<T: 'static + Write>(name: &OsStr, inner: T, algo: DigestAlgorithm) -> OutputHandle {
         OutputHandle {
             name: name.to_os_string(),
             inner: Box::new(inner),
-            digest: sha3::Sha3::sha3_256(),
+            digest: algo.start(),
         }
     }
 
@@ -58,26 +128,97 @@ impl OutputHandle {
         &self.name
     }
 
-    /// Consumes the object and returns the SHA256 sum of the content that was
-    /// written.
-    pub fn into_name_digest(mut self) -> (OsString, [u8; 32]) {
-        let mut r = [0u8; 32];
-        self.digest.result(&mut r);
+    /// Consumes the object and returns the digest of the content that was
+    /// written, or `None` if the handle was created with
+    /// `DigestAlgorithm::None`.
+    pub fn into_name_digest(mut self) -> (OsString, Option<Vec<u8>>) {
+        let r = self.digest.result();
         (self.name, r)
     }
 }
 
+#[cfg(not(feature = "std"))]
 impl Write for OutputHandle {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    fn write(&mut self, buf: &[u8]) -> traits::Result<usize> {
         self.digest.input(buf);
         self.inner.write(buf)
     }
 
-    fn flush(&mut self) -> io::Result<()> {
+    fn flush(&mut self) -> traits::Result<()> {
         self.inner.flush()
     }
 }
 
+// Give `OutputHandle` a `std::io::Write` impl on std builds so the engine's
+// std-based callers can still write to it; the blanket impl in `traits` then
+// supplies the crate-local `Write`.
+#[cfg(feature = "std")]
+impl ::std::io::Write for OutputHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.digest.input(buf);
+        self.inner.write(buf).map_err(Into::into)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().map_err(Into::into)
+    }
+}
+
+
+/// A record of the digests that outputs are expected to have, used to run a
+/// build in "verify" mode: as each output is closed its computed digest is
+/// compared against the recorded value and a mismatch is reported through the
+/// `StatusBackend`. An output with no recorded expectation is passed over
+/// silently; one that has an expectation but was written with
+/// `DigestAlgorithm::None` is reported as unverifiable and fails the check.
+#[derive(Clone, Debug, Default)]
+pub struct DigestVerifier {
+    expected: HashMap<OsString, Vec<u8>>,
+}
+
+impl DigestVerifier {
+    pub fn new() -> DigestVerifier {
+        DigestVerifier { expected: HashMap::new() }
+    }
+
+    /// Record the digest `name` is expected to have.
+    pub fn expect(&mut self, name: &OsStr, digest: Vec<u8>) {
+        self.expected.insert(name.to_os_string(), digest);
+    }
+
+    /// Close `handle` and verify the digest it computed against the recorded
+    /// expectation. This is the hook a verify-mode build runs as each output is
+    /// closed, so the comparison actually happens; a plain close just drops the
+    /// handle without consulting any expectation. Returns `true` if the output
+    /// matched (or had no expectation to check against).
+    pub fn close_and_check(&self, handle: OutputHandle, status: &mut StatusBackend) -> bool {
+        let (name, computed) = handle.into_name_digest();
+        self.check(&name, computed.as_ref().map(|v| &v[..]), status)
+    }
+
+    /// Check a freshly-computed digest against the recorded expectation,
+    /// reporting a mismatch through `status`. Returns `true` if the output
+    /// matched (or had no expectation to check against).
+    pub fn check(&self, name: &OsStr, computed: Option<&[u8]>, status: &mut StatusBackend) -> bool {
+        let expected = match self.expected.get(name) {
+            Some(e) => e,
+            None => return true,
+        };
+
+        match computed {
+            Some(c) if c == &expected[..] => true,
+            Some(_) => {
+                tt_error!(status, "digest mismatch for output `{}`", name.to_string_lossy());
+                false
+            },
+            None => {
+                tt_error!(status, "cannot verify output `{}`: its digest was not computed", name.to_string_lossy());
+                false
+            },
+        }
+    }
+}
+
 
 // An Io provider is a source of handles. One wrinkle is that it's good to be
 // able to distinguish between unavailability of a given name and error
@@ -91,6 +232,46 @@ pub enum OpenResult<T> {
     Err(Error)
 }
 
+
+// When an IoProvider genuinely fails -- as opposed to merely not knowing about
+// a given name -- the bare io::Error it gets back has no idea which provider,
+// file, or operation was involved, so a failure deep inside an IoStack
+// surfaces as an inscrutable "No such file or directory". We attach a small
+// bit of context at the point the error is raised so that it can be reported
+// as e.g. "failed to open `foo.tex` from the local cache bundle".
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IoErrorContext {
+    /// An operation on the named file, served by the named provider, failed.
+    /// The first field identifies the source (e.g. "the local cache bundle")
+    /// so the message says *which* provider failed, not just the path.
+    FileOp(&'static str, OsString),
+
+    /// Opening the process's standard output failed.
+    Stdout,
+
+    /// Fetching data from a bundle or over the network failed.
+    BundleFetch(String),
+}
+
+impl fmt::Display for IoErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IoErrorContext::FileOp(source, ref name) => write!(f, "failed to open `{}` from {}", name.to_string_lossy(), source),
+            IoErrorContext::Stdout => write!(f, "failed to open the standard output stream"),
+            IoErrorContext::BundleFetch(ref loc) => write!(f, "failed to fetch `{}` from the backing bundle", loc),
+        }
+    }
+}
+
+impl IoErrorContext {
+    /// Wrap an underlying `io::Error` with this context, producing an `Error`
+    /// that remembers both what went wrong and what we were trying to do.
+    pub fn wrap(self, e: io::Error) -> Error {
+        Error::with_chain(e, ErrorKind::Msg(format!("{}", self)))
+    }
+}
+
 pub trait IoProvider {
     fn output_open_name(&mut self, _name: &OsStr) -> OpenResult<OutputHandle> {
         OpenResult::NotAvailable
@@ -108,7 +289,7 @@ pub trait IoProvider {
 
 // Some generically helpful InputFeatures impls
 
-impl<R: Read> InputFeatures for GzDecoder<R> {
+impl<R: io::Read> InputFeatures for GzDecoder<R> {
     fn get_size(&mut self) -> Result<usize> {
         Err(ErrorKind::NotSizeable.into())
     }
@@ -132,15 +313,22 @@ impl InputFeatures for Cursor<Vec<u8>> {
 
 // Reexports
 
+#[cfg(feature = "std")]
 pub use self::filesystem::FilesystemIo;
+#[cfg(feature = "std")]
 pub use self::genuine_stdout::GenuineStdoutIo;
 pub use self::memory::MemoryIo;
+#[cfg(feature = "std")]
+pub use self::process::ProcessIo;
 pub use self::stack::IoStack;
 
 
 // Helpful.
 
-pub fn try_open_file(path: &Path) -> OpenResult<File> {
+/// Try to open a file on disk, attaching `source` (e.g. "the filesystem") to
+/// any genuine error so the failure names the provider it came from.
+#[cfg(feature = "std")]
+pub fn try_open_file(source: &'static str, path: &Path) -> OpenResult<File> {
     use std::io::ErrorKind::NotFound;
 
     match File::open(path) {
@@ -149,16 +337,101 @@ pub fn try_open_file(path: &Path) -> OpenResult<File> {
             if e.kind() == NotFound {
                 OpenResult::NotAvailable
             } else {
-                OpenResult::Err(e.into())
+                OpenResult::Err(IoErrorContext::FileOp(source, path.as_os_str().to_os_string()).wrap(e))
             }
         },
     }
 }
 
 
+// A cached bundle can be read by one Tectonic process while another is
+// concurrently populating the same cache directory. That races produce
+// spurious failures -- a file the index swears exists is briefly missing, or a
+// freshly-opened handle has the wrong length because the writer hasn't
+// finished -- which are transient and clear themselves once the writer lands
+// its atomic rename. `retry_open` retries such an open a few times, re-reading
+// the index between attempts, before giving up. Because it works from the
+// length the index records for a name, it only handles names the index lists,
+// and it retries every `NotAvailable` from the open as a race; a name the index
+// never mentions is simply absent, not racing, and has no recorded length to
+// hand it.
+
+/// The number of times a cache open is retried when it looks like a concurrent
+/// writer is to blame.
+#[cfg(feature = "std")]
+pub const CACHE_OPEN_RETRIES: usize = 5;
+
+/// Retry `open` while it keeps failing in a way consistent with a concurrent
+/// cache writer. `refresh` is invoked before each retry to re-read the
+/// manifest/index from disk. `path` is the on-disk cache file and `expected_len`
+/// the SHA-verified length the index records for it: the length is checked by
+/// stat-ing `path`, not by asking the opened handle, because cache inputs are
+/// frequently `GzDecoder` streams whose `get_size` is deliberately unsupported.
+/// A file shorter than `expected_len` is taken to be half-written by a racing
+/// writer and retried.
+///
+/// The index promised this name exists, so exhausting the retries surfaces a
+/// real error rather than `NotAvailable`.
+#[cfg(feature = "std")]
+pub fn retry_open<O, R>(path: &Path, expected_len: u64, mut open: O, mut refresh: R) -> OpenResult<InputHandle>
+    where O: FnMut() -> OpenResult<InputHandle>,
+          R: FnMut()
+{
+    const SOURCE: &str = "the local cache bundle";
+    let mut last_err = None;
+
+    for attempt in 0..=CACHE_OPEN_RETRIES {
+        if attempt > 0 {
+            thread::sleep(Duration::from_millis(10 * attempt as u64));
+            refresh();
+        }
+
+        match open() {
+            OpenResult::Ok(handle) => {
+                match ::std::fs::metadata(path) {
+                    // The file is the length the index recorded: the writer is
+                    // done, so this read is consistent.
+                    Ok(md) => if md.len() == expected_len {
+                        return OpenResult::Ok(handle);
+                    } else {
+                        // A wrong length is the half-written-file case this
+                        // function exists to handle. Remember the concrete
+                        // discrepancy so an exhausted retry reports it rather
+                        // than a generic timeout.
+                        let e = io::Error::new(io::ErrorKind::Other,
+                            format!("cached file has wrong length: expected {}, found {}", expected_len, md.len()));
+                        last_err = Some(IoErrorContext::FileOp(SOURCE, path.as_os_str().to_os_string()).wrap(e));
+                    },
+                    // A file the index swears exists is briefly missing while
+                    // the writer renames it into place; keep trying.
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                    Err(e) => {
+                        last_err = Some(IoErrorContext::FileOp(SOURCE, path.as_os_str().to_os_string()).wrap(e));
+                    },
+                }
+            },
+            // The index promised this name, so a missing file is a race, not a
+            // real absence: keep trying.
+            OpenResult::NotAvailable => {},
+            OpenResult::Err(e) => { last_err = Some(e); },
+        }
+    }
+
+    match last_err {
+        Some(e) => OpenResult::Err(e),
+        None => {
+            let e = io::Error::new(io::ErrorKind::Other,
+                format!("cache read kept racing a concurrent writer; gave up after {} retries", CACHE_OPEN_RETRIES));
+            OpenResult::Err(IoErrorContext::FileOp(SOURCE, path.as_os_str().to_os_string()).wrap(e))
+        },
+    }
+}
+
+
 // Helper for testing. FIXME: I want this to be conditionally compiled with
 // #[cfg(test)] but things break if I do that.
 
+#[cfg(feature = "std")]
 pub mod testing {
     use std::ffi::{OsStr, OsString};
     use std::fs::File;
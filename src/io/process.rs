@@ -0,0 +1,112 @@
+// src/io/process.rs -- an IoProvider that streams a helper command's stdout.
+// Copyright 2016-2017 the Tectonic Project
+// Licensed under the MIT License.
+
+//! An `IoProvider` that runs an external command and serves its standard
+//! output as an input file. This lets Tectonic pull in generated assets --
+//! converting an image, running a preprocessor -- by name, without the caller
+//! having to stage a temporary file first.
+
+use std::ffi::{OsStr, OsString};
+use std::io::{Cursor, Read};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use errors::Error;
+use status::StatusBackend;
+use super::{InputHandle, IoErrorContext, IoProvider, OpenResult, OutputHandle};
+
+
+pub struct ProcessIo {
+    /// The input name this provider answers to.
+    name: OsString,
+
+    /// The program to run.
+    program: OsString,
+
+    /// Arguments passed to the program.
+    args: Vec<OsString>,
+}
+
+
+impl ProcessIo {
+    pub fn new(name: &OsStr, program: &OsStr, args: &[OsString]) -> ProcessIo {
+        ProcessIo {
+            name: name.to_os_string(),
+            program: program.to_os_string(),
+            args: args.to_vec(),
+        }
+    }
+
+    fn run(&self, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        const SOURCE: &str = "a helper command";
+        let loc = self.program.to_string_lossy().into_owned();
+
+        let mut child = match Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn() {
+            Ok(c) => c,
+            Err(e) => return OpenResult::Err(IoErrorContext::FileOp(SOURCE, self.name.clone()).wrap(e)),
+        };
+
+        // The child's stderr must be drained concurrently with its stdout: a
+        // chatty helper that fills its stderr pipe buffer will block on the
+        // write forever if we only start reading stderr after stdout closes,
+        // deadlocking both sides. So pull stderr on its own thread while we
+        // read stdout here.
+        let mut stderr = child.stderr.take().expect("piped stderr missing");
+        let stderr_thread = thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = stderr.read_to_end(&mut buf);
+            buf
+        });
+
+        let mut stdout = child.stdout.take().expect("piped stdout missing");
+        let mut data = Vec::new();
+        let read_result = stdout.read_to_end(&mut data);
+
+        let captured = stderr_thread.join().unwrap_or_default();
+        let status_result = child.wait();
+
+        // Surface anything the helper printed on stderr through the status
+        // backend the engine already threaded in for us.
+        if !captured.is_empty() {
+            for line in String::from_utf8_lossy(&captured).lines() {
+                tt_warning!(status, "{}: {}", self.program.to_string_lossy(), line);
+            }
+        }
+
+        if let Err(e) = read_result {
+            return OpenResult::Err(IoErrorContext::FileOp(SOURCE, self.name.clone()).wrap(e));
+        }
+
+        match status_result {
+            Ok(es) if es.success() => OpenResult::Ok(Box::new(Cursor::new(data))),
+            Ok(es) => OpenResult::Err(Error::from(
+                format!("helper `{}` exited with {}", loc, es))),
+            Err(e) => OpenResult::Err(IoErrorContext::FileOp(SOURCE, self.name.clone()).wrap(e)),
+        }
+    }
+}
+
+
+impl IoProvider for ProcessIo {
+    fn output_open_name(&mut self, _: &OsStr) -> OpenResult<OutputHandle> {
+        OpenResult::NotAvailable
+    }
+
+    fn output_open_stdout(&mut self) -> OpenResult<OutputHandle> {
+        OpenResult::NotAvailable
+    }
+
+    fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        if name == self.name {
+            self.run(status)
+        } else {
+            OpenResult::NotAvailable
+        }
+    }
+}
@@ -0,0 +1,83 @@
+// src/io/stack.rs -- a stack of IoProviders tried in order.
+// Copyright 2016-2017 the Tectonic Project
+// Licensed under the MIT License.
+
+//! An `IoStack` chains several `IoProvider`s and tries them in order, returning
+//! the first one that can satisfy a request.
+
+use std::ffi::OsStr;
+
+use status::StatusBackend;
+use super::{InputHandle, IoProvider, OpenResult, OutputHandle};
+
+
+pub struct IoStack<'a> {
+    items: Vec<&'a mut IoProvider>,
+}
+
+impl<'a> IoStack<'a> {
+    pub fn new(items: Vec<&'a mut IoProvider>) -> IoStack<'a> {
+        IoStack { items: items }
+    }
+}
+
+
+// When every provider declines a name we return `NotAvailable`, but if one of
+// them actually *failed* -- returned `Err` rather than `NotAvailable` -- we
+// hold on to that error and surface it once the stack is exhausted, rather than
+// masking it as a bare "not available". This is what lets a failure deep in the
+// stack reach the user as "failed to open `foo.tex` ..." with its attached
+// IoErrorContext instead of a blank miss.
+
+impl<'a> IoProvider for IoStack<'a> {
+    fn output_open_name(&mut self, name: &OsStr) -> OpenResult<OutputHandle> {
+        let mut last_err = None;
+
+        for item in &mut self.items {
+            match item.output_open_name(name) {
+                OpenResult::NotAvailable => {},
+                OpenResult::Err(e) => { last_err = Some(e); },
+                r @ OpenResult::Ok(_) => return r,
+            }
+        }
+
+        match last_err {
+            Some(e) => OpenResult::Err(e),
+            None => OpenResult::NotAvailable,
+        }
+    }
+
+    fn output_open_stdout(&mut self) -> OpenResult<OutputHandle> {
+        let mut last_err = None;
+
+        for item in &mut self.items {
+            match item.output_open_stdout() {
+                OpenResult::NotAvailable => {},
+                OpenResult::Err(e) => { last_err = Some(e); },
+                r @ OpenResult::Ok(_) => return r,
+            }
+        }
+
+        match last_err {
+            Some(e) => OpenResult::Err(e),
+            None => OpenResult::NotAvailable,
+        }
+    }
+
+    fn input_open_name(&mut self, name: &OsStr, status: &mut StatusBackend) -> OpenResult<InputHandle> {
+        let mut last_err = None;
+
+        for item in &mut self.items {
+            match item.input_open_name(name, status) {
+                OpenResult::NotAvailable => {},
+                OpenResult::Err(e) => { last_err = Some(e); },
+                r @ OpenResult::Ok(_) => return r,
+            }
+        }
+
+        match last_err {
+            Some(e) => OpenResult::Err(e),
+            None => OpenResult::NotAvailable,
+        }
+    }
+}
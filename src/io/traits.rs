@@ -0,0 +1,132 @@
+// src/io/traits.rs -- crate-local Read/Write traits.
+// Copyright 2016-2017 the Tectonic Project
+// Licensed under the MIT License.
+
+//! `std::io::{Read, Write}` pin the engine's handle types to the standard
+//! library, which blocks running the engine where there is no `std` filesystem
+//! (WASM, embedded). Following the approach the `bitcoin` crate's `io` module
+//! uses, we mirror the pieces of `std::io` the engine needs as crate-local
+//! traits that are implementable without `std`: the core `Read`/`Write` methods
+//! take and return only slices and an `Error` that is alloc-free on its own, so
+//! a `no_std` target can implement them directly. On platforms built with the
+//! `std` feature the traits are bridged to `std::io` both ways -- a blanket
+//! impl adopts every `std::io::Read`/`Write` type, and the `impl_std_read!` /
+//! `impl_std_write!` macros derive `std::io` impls for crate-native types -- so
+//! existing callers keep passing the handles they always have.
+//!
+//! The `std` feature (default-on) lives in the crate manifest and gates the
+//! `std`-only bridges below plus the `filesystem`, `genuine_stdout`,
+//! `itarbundle`, `local_cache`, and `process` providers, `try_open_file`,
+//! `retry_open`, and the `testing` helpers in the parent module.
+
+use std::result;
+
+/// The error type for crate-local I/O. It carries a kind describing what went
+/// wrong and, on `std` platforms, the underlying `std::io::Error` so the cause
+/// is never discarded.
+#[derive(Debug)]
+pub enum Error {
+    /// The stream ended before the operation could complete.
+    UnexpectedEof,
+
+    /// Any other failure, described by a static message.
+    Other(&'static str),
+
+    /// The underlying `std::io::Error`, on platforms that have one.
+    #[cfg(feature = "std")]
+    Io(::std::io::Error),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+
+/// The `Read` half of the standard I/O traits. The core method deals only in
+/// byte slices, so it needs neither `std` nor `alloc` to implement.
+pub trait Read {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+}
+
+/// The `Write` half of the standard I/O traits. Slices only -- no `std`, no
+/// `alloc` required to implement.
+pub trait Write {
+    fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+    fn flush(&mut self) -> Result<()>;
+}
+
+
+// --- std bridges, compiled only with the `std` feature ---------------------
+
+#[cfg(feature = "std")]
+impl From<::std::io::Error> for Error {
+    fn from(e: ::std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Error> for ::std::io::Error {
+    fn from(e: Error) -> ::std::io::Error {
+        use std::io::{Error as IoError, ErrorKind};
+        match e {
+            Error::Io(e) => e,
+            Error::UnexpectedEof => IoError::new(ErrorKind::UnexpectedEof, "unexpected end of stream"),
+            Error::Other(m) => IoError::new(ErrorKind::Other, m),
+        }
+    }
+}
+
+// Every existing `std::io::Read`/`Write` implementor -- the filesystem,
+// `Cursor`, the gzip decoder -- satisfies the crate-local trait for free.
+
+#[cfg(feature = "std")]
+impl<T: ::std::io::Read> Read for T {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        ::std::io::Read::read(self, buf).map_err(Error::Io)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: ::std::io::Write> Write for T {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        ::std::io::Write::write(self, buf).map_err(Error::Io)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        ::std::io::Write::flush(self).map_err(Error::Io)
+    }
+}
+
+
+/// Derive `std::io::Read` for a type that only implements the crate-local
+/// `Read`, so a `no_std`-native handle still interoperates with `std` consumers
+/// when the crate is built with the `std` feature.
+#[macro_export]
+macro_rules! impl_std_read {
+    ($ty:ty) => {
+        #[cfg(feature = "std")]
+        impl ::std::io::Read for $ty {
+            fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+                $crate::io::traits::Read::read(self, buf).map_err(::std::convert::Into::into)
+            }
+        }
+    };
+}
+
+/// Derive `std::io::Write` for a type that only implements the crate-local
+/// `Write`. The companion to `impl_std_read!`.
+#[macro_export]
+macro_rules! impl_std_write {
+    ($ty:ty) => {
+        #[cfg(feature = "std")]
+        impl ::std::io::Write for $ty {
+            fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+                $crate::io::traits::Write::write(self, buf).map_err(::std::convert::Into::into)
+            }
+
+            fn flush(&mut self) -> ::std::io::Result<()> {
+                $crate::io::traits::Write::flush(self).map_err(::std::convert::Into::into)
+            }
+        }
+    };
+}